@@ -0,0 +1,65 @@
+use crate::Error;
+use byteorder::{BigEndian, ByteOrder};
+
+/// A cursor-like trait to read SSH-encoded things.
+pub trait Reader {
+    /// Create an SSH reader for `self`.
+    fn reader<'a>(&'a self, starting_at: usize) -> Position<'a>;
+}
+
+impl Reader for [u8] {
+    fn reader<'a>(&'a self, starting_at: usize) -> Position<'a> {
+        Position {
+            s: self,
+            position: starting_at,
+        }
+    }
+}
+
+/// A cursor-like type to read SSH-encoded values.
+#[derive(Debug)]
+pub struct Position<'a> {
+    s: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Position<'a> {
+    /// Read one length-prefixed string from this reader.
+    pub fn read_string(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.read_u32()? as usize;
+        if self.position + len <= self.s.len() {
+            let result = &self.s[self.position..(self.position + len)];
+            self.position += len;
+            Ok(result)
+        } else {
+            Err(Error::IndexOutOfBounds)
+        }
+    }
+
+    /// Read a `u32` from this reader.
+    pub fn read_u32(&mut self) -> Result<u32, Error> {
+        if self.position + 4 <= self.s.len() {
+            let u = BigEndian::read_u32(&self.s[self.position..]);
+            self.position += 4;
+            Ok(u)
+        } else {
+            Err(Error::IndexOutOfBounds)
+        }
+    }
+
+    /// Read one byte from this reader.
+    pub fn read_byte(&mut self) -> Result<u8, Error> {
+        if self.position + 1 <= self.s.len() {
+            let u = self.s[self.position];
+            self.position += 1;
+            Ok(u)
+        } else {
+            Err(Error::IndexOutOfBounds)
+        }
+    }
+
+    /// Read one length-prefixed multiple-precision integer from this reader.
+    pub fn read_mpint(&mut self) -> Result<&'a [u8], Error> {
+        self.read_string()
+    }
+}
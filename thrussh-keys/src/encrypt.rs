@@ -0,0 +1,243 @@
+//! Encrypt and decrypt arbitrary payloads to an SSH public key, the way
+//! `age` wraps a file key to an X25519 or RSA recipient. This lets any
+//! `ssh-ed25519` or `ssh-rsa` key this crate can already parse double as
+//! an encryption key, with no extra key management required.
+use crate::encoding::Reader;
+#[cfg(feature = "openssl")]
+use crate::format::openssh::write_mpint;
+use crate::format::openssh::{write_string, write_u32};
+use crate::key;
+use crate::Error;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use hkdf::Hkdf;
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256, Sha512};
+
+const ED25519_LABEL: &[u8] = b"age-encryption.org/v1/ssh-ed25519";
+const RSA_LABEL: &[u8] = b"age-encryption.org/v1/ssh-rsa";
+const FILE_KEY_LEN: usize = 32;
+
+fn recipient_tag(pubkey_wire: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(pubkey_wire);
+    let mut tag = [0; 4];
+    tag.copy_from_slice(&digest[..4]);
+    tag
+}
+
+fn ed25519_pubkey_to_x25519(pubkey: &[u8; 32]) -> Result<MontgomeryPoint, Error> {
+    CompressedEdwardsY(*pubkey)
+        .decompress()
+        .map(|p| p.to_montgomery())
+        .ok_or(Error::CouldNotReadKey)
+}
+
+fn ed25519_seckey_to_x25519(seed: &[u8; 32]) -> [u8; 32] {
+    let hash = Sha512::digest(seed);
+    let mut scalar = [0; 32];
+    scalar.copy_from_slice(&hash[..32]);
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+    scalar
+}
+
+fn wrap_key(wrapping_key: &[u8; 32], file_key: &[u8; FILE_KEY_LEN]) -> Result<Vec<u8>, Error> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(wrapping_key));
+    cipher
+        .encrypt(Nonce::from_slice(&[0; 12]), file_key.as_ref())
+        .map_err(|_| Error::CouldNotReadKey)
+}
+
+fn unwrap_key(wrapping_key: &[u8; 32], wrapped: &[u8]) -> Result<[u8; FILE_KEY_LEN], Error> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(wrapping_key));
+    let file_key = cipher
+        .decrypt(Nonce::from_slice(&[0; 12]), wrapped)
+        .map_err(|_| Error::CouldNotReadKey)?;
+    let mut out = [0; FILE_KEY_LEN];
+    out.copy_from_slice(&file_key);
+    Ok(out)
+}
+
+#[cfg(feature = "openssl")]
+fn rsa_oaep_sha256_encrypt<T: openssl::pkey::HasPublic>(
+    key: openssl::rsa::Rsa<T>,
+    file_key: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let pkey = openssl::pkey::PKey::from_rsa(key)?;
+    let mut ctx = openssl::pkey_ctx::PkeyCtx::new(&pkey)?;
+    ctx.encrypt_init()?;
+    ctx.set_rsa_padding(openssl::rsa::Padding::PKCS1_OAEP)?;
+    ctx.set_rsa_oaep_md(openssl::md::Md::sha256())?;
+    ctx.set_rsa_mgf1_md(openssl::md::Md::sha256())?;
+    ctx.set_rsa_oaep_label(RSA_LABEL)?;
+
+    let mut wrapped = Vec::new();
+    ctx.encrypt_to_vec(file_key, &mut wrapped)?;
+    Ok(wrapped)
+}
+
+#[cfg(feature = "openssl")]
+fn rsa_oaep_sha256_decrypt<T: openssl::pkey::HasPrivate>(
+    key: openssl::rsa::Rsa<T>,
+    wrapped: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let pkey = openssl::pkey::PKey::from_rsa(key)?;
+    let mut ctx = openssl::pkey_ctx::PkeyCtx::new(&pkey)?;
+    ctx.decrypt_init()?;
+    ctx.set_rsa_padding(openssl::rsa::Padding::PKCS1_OAEP)?;
+    ctx.set_rsa_oaep_md(openssl::md::Md::sha256())?;
+    ctx.set_rsa_mgf1_md(openssl::md::Md::sha256())?;
+    ctx.set_rsa_oaep_label(RSA_LABEL)?;
+
+    let mut file_key = Vec::new();
+    ctx.decrypt_to_vec(wrapped, &mut file_key)?;
+    Ok(file_key)
+}
+
+/// Encrypt `plaintext` so that only the holder of the secret half of
+/// `recipient` can decrypt it, using the same wrapping scheme as `age`'s
+/// `ssh-ed25519`/`ssh-rsa` recipient types.
+pub fn encrypt_to_ssh_key(recipient: &key::PublicKey, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut file_key = [0; FILE_KEY_LEN];
+    OsRng.fill_bytes(&mut file_key);
+
+    let mut out = Vec::new();
+    match recipient {
+        key::PublicKey::Ed25519(public) => {
+            let mut pubkey_wire = Vec::new();
+            write_string(&mut pubkey_wire, b"ssh-ed25519");
+            write_string(&mut pubkey_wire, &public.key);
+            write_string(&mut out, &recipient_tag(&pubkey_wire));
+
+            let recipient_share = ed25519_pubkey_to_x25519(&public.key)?;
+
+            let mut ephemeral_secret = [0; 32];
+            OsRng.fill_bytes(&mut ephemeral_secret);
+            ephemeral_secret[0] &= 248;
+            ephemeral_secret[31] &= 127;
+            ephemeral_secret[31] |= 64;
+            let ephemeral_share =
+                x25519_dalek::x25519(ephemeral_secret, curve25519_dalek::constants::X25519_BASEPOINT.0);
+            let shared_secret = x25519_dalek::x25519(ephemeral_secret, recipient_share.0);
+
+            let mut info = Vec::new();
+            info.extend_from_slice(&ephemeral_share);
+            info.extend_from_slice(&recipient_share.0);
+            info.extend_from_slice(ED25519_LABEL);
+
+            let mut wrapping_key = [0; 32];
+            Hkdf::<Sha256>::new(Some(&[]), &shared_secret)
+                .expand(&info, &mut wrapping_key)
+                .map_err(|_| Error::CouldNotReadKey)?;
+
+            write_string(&mut out, &ephemeral_share);
+            write_string(&mut out, &wrap_key(&wrapping_key, &file_key)?);
+        }
+        #[cfg(feature = "openssl")]
+        key::PublicKey::RSA { key, .. } => {
+            let mut pubkey_wire = Vec::new();
+            write_string(&mut pubkey_wire, b"ssh-rsa");
+            write_mpint(&mut pubkey_wire, &key.e().to_vec());
+            write_mpint(&mut pubkey_wire, &key.n().to_vec());
+            write_string(&mut out, &recipient_tag(&pubkey_wire));
+
+            let wrapped = rsa_oaep_sha256_encrypt(key.clone(), &file_key)?;
+            write_string(&mut out, &wrapped);
+        }
+        #[cfg(any(feature = "openssl", feature = "p256", feature = "p384", feature = "p521"))]
+        _ => return Err(Error::UnsupportedKeyType(Vec::new())),
+    }
+    write_u32(&mut out, FILE_KEY_LEN as u32);
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&file_key));
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&[0; 12]), plaintext)
+        .map_err(|_| Error::CouldNotReadKey)?;
+    write_string(&mut out, &ciphertext);
+    Ok(out)
+}
+
+/// Decrypt a payload produced by [`encrypt_to_ssh_key`] using the secret
+/// key it was wrapped to.
+pub fn decrypt_with_ssh_key(key: &key::KeyPair, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut position = ciphertext.reader(0);
+    let _tag = position.read_string()?;
+
+    let file_key = match key {
+        key::KeyPair::Ed25519(secret) => {
+            let ephemeral_share = position.read_string()?;
+            let wrapped = position.read_string()?;
+
+            let mut seed = [0; 32];
+            seed.copy_from_slice(&secret.key[..32]);
+            let x25519_secret = ed25519_seckey_to_x25519(&seed);
+
+            let mut ephemeral = [0; 32];
+            ephemeral.copy_from_slice(ephemeral_share);
+            let shared_secret = x25519_dalek::x25519(x25519_secret, ephemeral);
+
+            let recipient_share = ed25519_pubkey_to_x25519(
+                &secret.key[32..].try_into().map_err(|_| Error::CouldNotReadKey)?,
+            )?;
+
+            let mut info = Vec::new();
+            info.extend_from_slice(ephemeral_share);
+            info.extend_from_slice(&recipient_share.0);
+            info.extend_from_slice(ED25519_LABEL);
+
+            let mut wrapping_key = [0; 32];
+            Hkdf::<Sha256>::new(Some(&[]), &shared_secret)
+                .expand(&info, &mut wrapping_key)
+                .map_err(|_| Error::CouldNotReadKey)?;
+
+            unwrap_key(&wrapping_key, wrapped)?
+        }
+        #[cfg(feature = "openssl")]
+        key::KeyPair::RSA { key, .. } => {
+            let wrapped = position.read_string()?;
+            let file_key = rsa_oaep_sha256_decrypt(key.clone(), wrapped)?;
+            let mut out = [0; FILE_KEY_LEN];
+            out.copy_from_slice(&file_key);
+            out
+        }
+        #[cfg(any(feature = "openssl", feature = "p256", feature = "p384", feature = "p521"))]
+        _ => return Err(Error::UnsupportedKeyType(Vec::new())),
+    };
+
+    let _file_key_len = position.read_u32()?;
+    let payload = position.read_string()?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&file_key));
+    cipher
+        .decrypt(Nonce::from_slice(&[0; 12]), payload)
+        .map_err(|_| Error::CouldNotReadKey.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ed25519_encrypt_decrypt_round_trips() {
+        let pair = key::KeyPair::generate_ed25519();
+        let public = pair.clone_public_key().unwrap();
+
+        let plaintext = b"the quick brown fox jumps over the lazy dog";
+        let ciphertext = encrypt_to_ssh_key(&public, plaintext).unwrap();
+        let decrypted = decrypt_with_ssh_key(&pair, &ciphertext).unwrap();
+
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn decrypting_with_the_wrong_key_fails() {
+        let recipient = key::KeyPair::generate_ed25519();
+        let other = key::KeyPair::generate_ed25519();
+        let public = recipient.clone_public_key().unwrap();
+
+        let ciphertext = encrypt_to_ssh_key(&public, b"secret payload").unwrap();
+        assert!(decrypt_with_ssh_key(&other, &ciphertext).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use crate::Error;
+
+/// A block/stream cipher backend for decrypting and encrypting OpenSSH
+/// private key blobs. Two implementations are available behind cargo
+/// features, so a deployment that must route all cryptography through a
+/// validated provider can build with only one of them linked in: the
+/// default pure-Rust backend, or an openssl-backed one for FIPS-capable
+/// deployments.
+pub(crate) trait Cipher {
+    fn decrypt(&self, ciphername: &[u8], key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, Error>;
+    fn encrypt(&self, ciphername: &[u8], key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+#[cfg(feature = "rust-crypto-cipher")]
+pub(crate) struct RustCryptoCipher;
+
+#[cfg(feature = "rust-crypto-cipher")]
+impl Cipher for RustCryptoCipher {
+    fn decrypt(&self, ciphername: &[u8], key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        use aes::cipher::{NewCipher, StreamCipher};
+        use aes::*;
+        use block_modes::block_padding::NoPadding;
+        use block_modes::BlockMode;
+        type Aes128Cbc = block_modes::Cbc<Aes128, NoPadding>;
+        type Aes256Cbc = block_modes::Cbc<Aes256, NoPadding>;
+
+        let mut dec = data.to_vec();
+        match ciphername {
+            b"aes128-cbc" => {
+                let cipher = Aes128Cbc::new_from_slices(key, iv).map_err(|_| Error::KeyIntegrity)?;
+                let n = cipher.decrypt(&mut dec)?.len();
+                dec.truncate(n);
+            }
+            b"aes256-cbc" => {
+                let cipher = Aes256Cbc::new_from_slices(key, iv).map_err(|_| Error::KeyIntegrity)?;
+                let n = cipher.decrypt(&mut dec)?.len();
+                dec.truncate(n);
+            }
+            b"aes128-ctr" => {
+                let mut cipher = Aes128Ctr::new_from_slices(key, iv).map_err(|_| Error::KeyIntegrity)?;
+                cipher.apply_keystream(&mut dec);
+                dec.truncate(data.len());
+            }
+            b"aes256-ctr" => {
+                let mut cipher = Aes256Ctr::new_from_slices(key, iv).map_err(|_| Error::KeyIntegrity)?;
+                cipher.apply_keystream(&mut dec);
+                dec.truncate(data.len());
+            }
+            _ => return Err(Error::CouldNotReadKey),
+        }
+        Ok(dec)
+    }
+
+    fn encrypt(&self, ciphername: &[u8], key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        use aes::cipher::{NewCipher, StreamCipher};
+        use aes::*;
+        use block_modes::block_padding::NoPadding;
+        use block_modes::BlockMode;
+        type Aes128Cbc = block_modes::Cbc<Aes128, NoPadding>;
+        type Aes256Cbc = block_modes::Cbc<Aes256, NoPadding>;
+
+        let mut enc = data.to_vec();
+        match ciphername {
+            b"aes128-cbc" => {
+                let cipher = Aes128Cbc::new_from_slices(key, iv).map_err(|_| Error::KeyIntegrity)?;
+                enc = cipher.encrypt_vec(&enc);
+            }
+            b"aes256-cbc" => {
+                let cipher = Aes256Cbc::new_from_slices(key, iv).map_err(|_| Error::KeyIntegrity)?;
+                enc = cipher.encrypt_vec(&enc);
+            }
+            b"aes128-ctr" => {
+                let mut cipher = Aes128Ctr::new_from_slices(key, iv).map_err(|_| Error::KeyIntegrity)?;
+                cipher.apply_keystream(&mut enc);
+            }
+            b"aes256-ctr" => {
+                let mut cipher = Aes256Ctr::new_from_slices(key, iv).map_err(|_| Error::KeyIntegrity)?;
+                cipher.apply_keystream(&mut enc);
+            }
+            _ => return Err(Error::CouldNotReadKey),
+        }
+        Ok(enc)
+    }
+}
+
+#[cfg(feature = "openssl-cipher")]
+pub(crate) struct OpensslCipher;
+
+#[cfg(feature = "openssl-cipher")]
+impl OpensslCipher {
+    fn run(
+        &self,
+        mode: openssl::symm::Mode,
+        ciphername: &[u8],
+        key: &[u8],
+        iv: &[u8],
+        data: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let t = match ciphername {
+            b"aes128-cbc" => openssl::symm::Cipher::aes_128_cbc(),
+            b"aes256-cbc" => openssl::symm::Cipher::aes_256_cbc(),
+            b"aes128-ctr" => openssl::symm::Cipher::aes_128_ctr(),
+            b"aes256-ctr" => openssl::symm::Cipher::aes_256_ctr(),
+            _ => return Err(Error::CouldNotReadKey),
+        };
+        let mut crypter = openssl::symm::Crypter::new(t, mode, key, Some(iv))?;
+        crypter.pad(false);
+        let mut out = vec![0; data.len() + t.block_size()];
+        let count = crypter.update(data, &mut out)?;
+        let rest = crypter.finalize(&mut out[count..])?;
+        out.truncate(count + rest);
+        Ok(out)
+    }
+}
+
+#[cfg(feature = "openssl-cipher")]
+impl Cipher for OpensslCipher {
+    fn decrypt(&self, ciphername: &[u8], key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.run(openssl::symm::Mode::Decrypt, ciphername, key, iv, data)
+    }
+
+    fn encrypt(&self, ciphername: &[u8], key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        self.run(openssl::symm::Mode::Encrypt, ciphername, key, iv, data)
+    }
+}
+
+#[cfg(feature = "openssl-cipher")]
+pub(crate) fn backend() -> impl Cipher {
+    OpensslCipher
+}
+
+#[cfg(all(not(feature = "openssl-cipher"), feature = "rust-crypto-cipher"))]
+pub(crate) fn backend() -> impl Cipher {
+    RustCryptoCipher
+}
+
+#[cfg(not(any(feature = "openssl-cipher", feature = "rust-crypto-cipher")))]
+compile_error!(
+    "thrussh-keys: enable at least one of the `rust-crypto-cipher` or `openssl-cipher` \
+     features to select an OpenSSH key cipher backend (`openssl-cipher` is used when both are)"
+);
+
+/// Authenticate and decrypt an `aes256-gcm@openssh.com` private key
+/// section. Unlike the CBC/CTR ciphers above, this is an AEAD: `tag` must
+/// be verified before any plaintext is returned.
+#[cfg(feature = "openssl")]
+pub(crate) fn aes256_gcm_decrypt(
+    key: &[u8],
+    iv: &[u8],
+    ciphertext: &[u8],
+    tag: &[u8],
+) -> Result<Vec<u8>, Error> {
+    openssl::symm::decrypt_aead(openssl::symm::Cipher::aes_256_gcm(), key, Some(iv), &[], ciphertext, tag)
+        .map_err(|_| Error::KeyIntegrity)
+}
+
+#[cfg(feature = "openssl")]
+pub(crate) fn aes256_gcm_encrypt(key: &[u8], iv: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut tag = [0; 16];
+    let mut ciphertext = openssl::symm::encrypt_aead(
+        openssl::symm::Cipher::aes_256_gcm(),
+        key,
+        Some(iv),
+        &[],
+        plaintext,
+        &mut tag,
+    )?;
+    ciphertext.extend_from_slice(&tag);
+    Ok(ciphertext)
+}
+
+#[cfg(all(test, feature = "rust-crypto-cipher", feature = "openssl-cipher"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rust_crypto_and_openssl_backends_agree() {
+        let key32 = [0x42; 32];
+        let iv = [0x24; 16];
+        let plaintext = b"0123456789abcdef0123456789abcdef";
+
+        for ciphername in [&b"aes128-cbc"[..], b"aes256-cbc", b"aes128-ctr", b"aes256-ctr"] {
+            let key_len = if ciphername.starts_with(b"aes128-") { 16 } else { 32 };
+            let key = &key32[..key_len];
+
+            let rust_crypto = RustCryptoCipher.encrypt(ciphername, key, &iv, plaintext).unwrap();
+            let openssl_out = OpensslCipher.encrypt(ciphername, key, &iv, plaintext).unwrap();
+            assert_eq!(rust_crypto, openssl_out, "backends disagree for {:?}", ciphername);
+
+            let rust_crypto_decrypted = RustCryptoCipher.decrypt(ciphername, key, &iv, &openssl_out).unwrap();
+            assert_eq!(rust_crypto_decrypted, plaintext);
+        }
+    }
+}
@@ -0,0 +1,4 @@
+pub mod openssh;
+pub use self::openssh::*;
+
+pub(crate) mod cipher;
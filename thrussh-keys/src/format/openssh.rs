@@ -1,14 +1,29 @@
 use crate::encoding::Reader;
 use crate::key;
-use crate::{Error, KEYTYPE_ED25519, KEYTYPE_P256, KEYTYPE_RSA};
+use crate::{
+    Error, KEYTYPE_DSS, KEYTYPE_ED25519, KEYTYPE_P256, KEYTYPE_P384, KEYTYPE_P521, KEYTYPE_RSA,
+};
 use bcrypt_pbkdf;
 #[cfg(feature = "openssl")]
 use openssl::bn::BigNum;
+use rand::{rngs::OsRng, RngCore};
+
+#[cfg(any(feature = "p384", feature = "p521"))]
+fn read_fixed_mpint<const N: usize>(sec_bytes: &[u8]) -> [u8; N] {
+    let mut key_bytes = [0u8; N];
+    // Reading mpints in a naive way, but sufficient for modular values here
+    sec_bytes.iter().rev().enumerate().for_each(|(i, b)| {
+        if i < N {
+            key_bytes[N - 1 - i] = *b;
+        }
+    });
+    key_bytes
+}
 
 /// Decode a secret key given in the OpenSSH format, deciphering it if
 /// needed using the supplied password.
 pub fn decode_openssh(secret: &[u8], password: Option<&str>) -> Result<key::KeyPair, Error> {
-    if &secret[0..15] == b"openssh-key-v1\0" {
+    if secret.len() >= 15 && &secret[0..15] == b"openssh-key-v1\0" {
         let mut position = secret.reader(15);
 
         let ciphername = position.read_string()?;
@@ -34,7 +49,9 @@ pub fn decode_openssh(secret: &[u8], password: Option<&str>) -> Result<key::KeyP
                 let pubkey = position.read_string()?;
                 let seckey = position.read_string()?;
                 let _comment = position.read_string()?;
-                assert_eq!(pubkey, &seckey[32..]);
+                if seckey.len() != 64 || pubkey != &seckey[32..] {
+                    return Err(Error::KeyIntegrity);
+                }
                 use key::ed25519::*;
                 let mut secret = SecretKey::new_zeroed();
                 secret.key.clone_from_slice(seckey);
@@ -64,7 +81,7 @@ pub fn decode_openssh(secret: &[u8], password: Option<&str>) -> Result<key::KeyP
                         .set_factors(p, q)?
                         .set_crt_params(dmp1, dmq1, iqmp)?
                         .build();
-                    key.check_key().unwrap();
+                    key.check_key().map_err(|_| Error::KeyIntegrity)?;
                     return Ok(key::KeyPair::RSA {
                         key,
                         hash: key::SignatureHash::SHA2_512,
@@ -84,12 +101,58 @@ pub fn decode_openssh(secret: &[u8], password: Option<&str>) -> Result<key::KeyP
                             key_bytes[31 - i] = *b;
                         }
                     });
-                    let key = p256::SecretKey::from_bytes(&key_bytes.into())?;
+                    let key = p256::SecretKey::from_bytes(&key_bytes.into())
+                        .map_err(|e| Error::EllipticCurve(e.to_string()))?;
                     if key.public_key().to_sec1_bytes().as_ref() != pub_bytes {
                         return Err(Error::CouldNotReadKey);
                     }
                     return Ok(key::KeyPair::P256(key));
                 }
+            } else if key_type == KEYTYPE_P384 && cfg!(feature = "p384") {
+                #[cfg(feature = "p384")]
+                {
+                    let _nistp384 = position.read_string()?;
+                    let pub_bytes = position.read_string()?;
+                    let sec_bytes = position.read_mpint()?;
+                    let _comment = position.read_string()?;
+                    let key_bytes = read_fixed_mpint::<48>(sec_bytes);
+                    let key = p384::SecretKey::from_bytes(&key_bytes.into())
+                        .map_err(|e| Error::EllipticCurve(e.to_string()))?;
+                    if key.public_key().to_sec1_bytes().as_ref() != pub_bytes {
+                        return Err(Error::CouldNotReadKey);
+                    }
+                    return Ok(key::KeyPair::P384(key));
+                }
+            } else if key_type == KEYTYPE_P521 && cfg!(feature = "p521") {
+                #[cfg(feature = "p521")]
+                {
+                    let _nistp521 = position.read_string()?;
+                    let pub_bytes = position.read_string()?;
+                    let sec_bytes = position.read_mpint()?;
+                    let _comment = position.read_string()?;
+                    let key_bytes = read_fixed_mpint::<66>(sec_bytes);
+                    let key = p521::SecretKey::from_bytes(
+                        p521::elliptic_curve::generic_array::GenericArray::from_slice(&key_bytes),
+                    )
+                    .map_err(|e| Error::EllipticCurve(e.to_string()))?;
+                    if key.public_key().to_sec1_bytes().as_ref() != pub_bytes {
+                        return Err(Error::CouldNotReadKey);
+                    }
+                    return Ok(key::KeyPair::P521(key));
+                }
+            } else if key_type == KEYTYPE_DSS && cfg!(feature = "openssl") {
+                #[cfg(feature = "openssl")]
+                {
+                    let p = BigNum::from_slice(position.read_string()?)?;
+                    let q = BigNum::from_slice(position.read_string()?)?;
+                    let g = BigNum::from_slice(position.read_string()?)?;
+                    let y = BigNum::from_slice(position.read_string()?)?;
+                    let x = BigNum::from_slice(position.read_string()?)?;
+                    let _comment = position.read_string()?;
+
+                    let key = openssl::dsa::Dsa::from_private_components(p, q, g, x, y)?;
+                    return Ok(key::KeyPair::DSA(key));
+                }
             } else {
                 return Err(Error::UnsupportedKeyType(key_type.to_vec()).into());
             }
@@ -100,10 +163,181 @@ pub fn decode_openssh(secret: &[u8], password: Option<&str>) -> Result<key::KeyP
     }
 }
 
-use aes::*;
-use block_modes::block_padding::NoPadding;
-type Aes128Cbc = block_modes::Cbc<Aes128, NoPadding>;
-type Aes256Cbc = block_modes::Cbc<Aes256, NoPadding>;
+/// Encode a key pair into the OpenSSH private key format, optionally
+/// encrypting it with the supplied password. This is the inverse of
+/// [`decode_openssh`].
+pub fn encode_openssh(key: &key::KeyPair, password: Option<&str>) -> Result<Vec<u8>, Error> {
+    let mut encoded = Vec::new();
+    encoded.extend_from_slice(b"openssh-key-v1\0");
+
+    let (ciphername, kdfname, kdfoptions): (&[u8], &[u8], Vec<u8>) = if password.is_some() {
+        let mut salt = [0; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut kdfoptions = Vec::new();
+        write_string(&mut kdfoptions, &salt);
+        write_u32(&mut kdfoptions, 16);
+        (b"aes256-cbc", b"bcrypt", kdfoptions)
+    } else {
+        (b"none", b"none", Vec::new())
+    };
+    write_string(&mut encoded, ciphername);
+    write_string(&mut encoded, kdfname);
+    write_string(&mut encoded, &kdfoptions);
+
+    write_u32(&mut encoded, 1); // nkeys
+
+    let mut pubkey = Vec::new();
+    let mut privkey = Vec::new();
+
+    match key {
+        key::KeyPair::Ed25519(secret) => {
+            write_string(&mut pubkey, KEYTYPE_ED25519);
+            write_string(&mut pubkey, &secret.key[32..]);
+
+            write_string(&mut privkey, KEYTYPE_ED25519);
+            write_string(&mut privkey, &secret.key[32..]);
+            write_string(&mut privkey, &secret.key[..]);
+            write_string(&mut privkey, b"");
+        }
+        #[cfg(feature = "openssl")]
+        key::KeyPair::RSA { key, .. } => {
+            let n = key.n().to_vec();
+            let e = key.e().to_vec();
+            let d = key.d().to_vec();
+            let p = key.p().ok_or(Error::CouldNotReadKey)?.to_vec();
+            let q = key.q().ok_or(Error::CouldNotReadKey)?.to_vec();
+            let iqmp = key.iqmp().ok_or(Error::CouldNotReadKey)?.to_vec();
+
+            write_string(&mut pubkey, KEYTYPE_RSA);
+            write_mpint(&mut pubkey, &e);
+            write_mpint(&mut pubkey, &n);
+
+            write_string(&mut privkey, KEYTYPE_RSA);
+            write_mpint(&mut privkey, &n);
+            write_mpint(&mut privkey, &e);
+            write_mpint(&mut privkey, &d);
+            write_mpint(&mut privkey, &iqmp);
+            write_mpint(&mut privkey, &p);
+            write_mpint(&mut privkey, &q);
+            write_string(&mut privkey, b"");
+        }
+        #[cfg(feature = "p256")]
+        key::KeyPair::P256(secret) => {
+            let pub_bytes = secret.public_key().to_sec1_bytes();
+
+            write_string(&mut pubkey, KEYTYPE_P256);
+            write_string(&mut pubkey, b"nistp256");
+            write_string(&mut pubkey, &pub_bytes);
+
+            write_string(&mut privkey, KEYTYPE_P256);
+            write_string(&mut privkey, b"nistp256");
+            write_string(&mut privkey, &pub_bytes);
+            write_mpint(&mut privkey, &secret.to_bytes());
+            write_string(&mut privkey, b"");
+        }
+        #[cfg(feature = "p384")]
+        key::KeyPair::P384(secret) => {
+            let pub_bytes = secret.public_key().to_sec1_bytes();
+
+            write_string(&mut pubkey, KEYTYPE_P384);
+            write_string(&mut pubkey, b"nistp384");
+            write_string(&mut pubkey, &pub_bytes);
+
+            write_string(&mut privkey, KEYTYPE_P384);
+            write_string(&mut privkey, b"nistp384");
+            write_string(&mut privkey, &pub_bytes);
+            write_mpint(&mut privkey, &secret.to_bytes());
+            write_string(&mut privkey, b"");
+        }
+        #[cfg(feature = "p521")]
+        key::KeyPair::P521(secret) => {
+            let pub_bytes = secret.public_key().to_sec1_bytes();
+
+            write_string(&mut pubkey, KEYTYPE_P521);
+            write_string(&mut pubkey, b"nistp521");
+            write_string(&mut pubkey, &pub_bytes);
+
+            write_string(&mut privkey, KEYTYPE_P521);
+            write_string(&mut privkey, b"nistp521");
+            write_string(&mut privkey, &pub_bytes);
+            write_mpint(&mut privkey, &secret.to_bytes());
+            write_string(&mut privkey, b"");
+        }
+        #[cfg(feature = "openssl")]
+        key::KeyPair::DSA(key) => {
+            let p = key.p().to_vec();
+            let q = key.q().to_vec();
+            let g = key.g().to_vec();
+            let y = key.pub_key().to_vec();
+            let x = key.priv_key().to_vec();
+
+            write_string(&mut pubkey, KEYTYPE_DSS);
+            write_mpint(&mut pubkey, &p);
+            write_mpint(&mut pubkey, &q);
+            write_mpint(&mut pubkey, &g);
+            write_mpint(&mut pubkey, &y);
+
+            write_string(&mut privkey, KEYTYPE_DSS);
+            write_mpint(&mut privkey, &p);
+            write_mpint(&mut privkey, &q);
+            write_mpint(&mut privkey, &g);
+            write_mpint(&mut privkey, &y);
+            write_mpint(&mut privkey, &x);
+            write_string(&mut privkey, b"");
+        }
+    }
+
+    let mut private_section = Vec::new();
+    let mut check = [0; 4];
+    OsRng.fill_bytes(&mut check);
+    private_section.extend_from_slice(&check);
+    private_section.extend_from_slice(&check);
+    private_section.extend_from_slice(&privkey);
+
+    let block_size = if ciphername == b"none" { 8 } else { 16 };
+    let mut pad = 1u8;
+    while private_section.len() % block_size != 0 {
+        private_section.push(pad);
+        pad = pad.wrapping_add(1);
+    }
+
+    let private_section = if let Some(password) = password {
+        encrypt_secret_key(ciphername, &kdfoptions, password, &private_section)?
+    } else {
+        private_section
+    };
+
+    write_string(&mut encoded, &pubkey);
+    write_string(&mut encoded, &private_section);
+
+    Ok(encoded)
+}
+
+pub(crate) fn write_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_be_bytes());
+}
+
+pub(crate) fn write_string(buf: &mut Vec<u8>, s: &[u8]) {
+    write_u32(buf, s.len() as u32);
+    buf.extend_from_slice(s);
+}
+
+pub(crate) fn write_mpint(buf: &mut Vec<u8>, n: &[u8]) {
+    let mut n = n;
+    while n.len() > 1 && n[0] == 0 {
+        n = &n[1..];
+    }
+    if !n.is_empty() && n[0] & 0x80 != 0 {
+        write_u32(buf, n.len() as u32 + 1);
+        buf.push(0);
+        buf.extend_from_slice(n);
+    } else {
+        write_string(buf, n);
+    }
+}
+
+use super::cipher;
+use super::cipher::Cipher;
 
 fn decrypt_secret_key(
     ciphername: &[u8],
@@ -120,53 +354,194 @@ fn decrypt_secret_key(
         }
     } else if let Some(password) = password {
         let mut key = [0; 48];
-        let n = match ciphername {
-            b"aes128-cbc" | b"aes128-ctr" => 32,
-            b"aes256-cbc" | b"aes256-ctr" => 48,
+        let (key_len, iv_len) = match ciphername {
+            b"aes128-cbc" | b"aes128-ctr" => (16, 16),
+            b"aes256-cbc" | b"aes256-ctr" => (32, 16),
+            b"aes256-gcm@openssh.com" => (32, 12),
             _ => return Err(Error::CouldNotReadKey.into()),
         };
+        let n = key_len + iv_len;
         match kdfname {
             b"bcrypt" => {
                 let mut kdfopts = kdfoptions.reader(0);
                 let salt = kdfopts.read_string()?;
                 let rounds = kdfopts.read_u32()?;
-                bcrypt_pbkdf::bcrypt_pbkdf(password, salt, rounds, &mut key[..n]).unwrap();
+                bcrypt_pbkdf::bcrypt_pbkdf(password, salt, rounds, &mut key[..n])
+                    .map_err(|_| Error::KeyIntegrity)?;
             }
             _kdfname => {
                 return Err(Error::CouldNotReadKey.into());
             }
         };
-        let (key, iv) = key.split_at(n - 16);
-
-        let mut dec = secret_key.to_vec();
-        dec.resize(dec.len() + 32, 0u8);
-        use aes::cipher::{NewCipher, StreamCipher};
-        use block_modes::BlockMode;
-        match ciphername {
-            b"aes128-cbc" => {
-                let cipher = Aes128Cbc::new_from_slices(key, iv).unwrap();
-                let n = cipher.decrypt(&mut dec)?.len();
-                dec.truncate(n)
-            }
-            b"aes256-cbc" => {
-                let cipher = Aes256Cbc::new_from_slices(key, iv).unwrap();
-                let n = cipher.decrypt(&mut dec)?.len();
-                dec.truncate(n)
-            }
-            b"aes128-ctr" => {
-                let mut cipher = Aes128Ctr::new_from_slices(key, iv).unwrap();
-                cipher.apply_keystream(&mut dec);
-                dec.truncate(secret_key.len())
+        let (key, iv) = key[..n].split_at(key_len);
+        if ciphername == b"aes256-gcm@openssh.com" {
+            #[cfg(feature = "openssl")]
+            {
+                if secret_key.len() < 16 {
+                    return Err(Error::KeyIntegrity);
+                }
+                let (ciphertext, tag) = secret_key.split_at(secret_key.len() - 16);
+                cipher::aes256_gcm_decrypt(key, iv, ciphertext, tag)
             }
-            b"aes256-ctr" => {
-                let mut cipher = Aes256Ctr::new_from_slices(key, iv).unwrap();
-                cipher.apply_keystream(&mut dec);
-                dec.truncate(secret_key.len())
+            #[cfg(not(feature = "openssl"))]
+            {
+                Err(Error::UnsupportedKeyType(ciphername.to_vec()))
             }
-            _ => {}
+        } else {
+            cipher::backend().decrypt(ciphername, key, iv, secret_key)
         }
-        Ok(dec)
     } else {
         Err(Error::KeyIsEncrypted.into())
     }
 }
+
+fn encrypt_secret_key(
+    ciphername: &[u8],
+    kdfoptions: &[u8],
+    password: &str,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let mut key = [0; 48];
+    let (key_len, iv_len) = match ciphername {
+        b"aes128-cbc" | b"aes128-ctr" => (16, 16),
+        b"aes256-cbc" | b"aes256-ctr" => (32, 16),
+        b"aes256-gcm@openssh.com" => (32, 12),
+        _ => return Err(Error::CouldNotReadKey.into()),
+    };
+    let n = key_len + iv_len;
+    let mut kdfopts = kdfoptions.reader(0);
+    let salt = kdfopts.read_string()?;
+    let rounds = kdfopts.read_u32()?;
+    bcrypt_pbkdf::bcrypt_pbkdf(password, salt, rounds, &mut key[..n]).map_err(|_| Error::KeyIntegrity)?;
+    let (key, iv) = key[..n].split_at(key_len);
+    if ciphername == b"aes256-gcm@openssh.com" {
+        #[cfg(feature = "openssl")]
+        {
+            cipher::aes256_gcm_encrypt(key, iv, plaintext)
+        }
+        #[cfg(not(feature = "openssl"))]
+        {
+            Err(Error::UnsupportedKeyType(ciphername.to_vec()))
+        }
+    } else {
+        cipher::backend().encrypt(ciphername, key, iv, plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ed25519_round_trips_unencrypted() {
+        let pair = key::KeyPair::generate_ed25519();
+        let encoded = encode_openssh(&pair, None).unwrap();
+        let decoded = decode_openssh(&encoded, None).unwrap();
+        match (pair, decoded) {
+            (key::KeyPair::Ed25519(a), key::KeyPair::Ed25519(b)) => assert_eq!(a.key, b.key),
+            _ => panic!("expected an Ed25519 key pair to round-trip as Ed25519"),
+        }
+    }
+
+    #[test]
+    fn ed25519_round_trips_with_password() {
+        let pair = key::KeyPair::generate_ed25519();
+        let encoded = encode_openssh(&pair, Some("hunter2")).unwrap();
+        let decoded = decode_openssh(&encoded, Some("hunter2")).unwrap();
+        match (pair, decoded) {
+            (key::KeyPair::Ed25519(a), key::KeyPair::Ed25519(b)) => assert_eq!(a.key, b.key),
+            _ => panic!("expected an Ed25519 key pair to round-trip as Ed25519"),
+        }
+    }
+
+    #[test]
+    fn wrong_password_is_rejected() {
+        let pair = key::KeyPair::generate_ed25519();
+        let encoded = encode_openssh(&pair, Some("hunter2")).unwrap();
+        assert!(decode_openssh(&encoded, Some("not-it")).is_err());
+    }
+
+    #[cfg(feature = "p384")]
+    #[test]
+    fn p384_round_trips_unencrypted() {
+        let secret = p384::SecretKey::random(&mut rand::rngs::OsRng);
+        let pair = key::KeyPair::P384(secret);
+        let encoded = encode_openssh(&pair, None).unwrap();
+        let decoded = decode_openssh(&encoded, None).unwrap();
+        match (pair, decoded) {
+            (key::KeyPair::P384(a), key::KeyPair::P384(b)) => assert_eq!(a.to_bytes(), b.to_bytes()),
+            _ => panic!("expected a P384 key pair to round-trip as P384"),
+        }
+    }
+
+    #[cfg(feature = "p521")]
+    #[test]
+    fn p521_round_trips_unencrypted() {
+        let secret = p521::SecretKey::random(&mut rand::rngs::OsRng);
+        let pair = key::KeyPair::P521(secret);
+        let encoded = encode_openssh(&pair, None).unwrap();
+        let decoded = decode_openssh(&encoded, None).unwrap();
+        match (pair, decoded) {
+            (key::KeyPair::P521(a), key::KeyPair::P521(b)) => assert_eq!(a.to_bytes(), b.to_bytes()),
+            _ => panic!("expected a P521 key pair to round-trip as P521"),
+        }
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn dsa_round_trips_unencrypted() {
+        let key = openssl::dsa::Dsa::generate(1024).unwrap();
+        let pair = key::KeyPair::DSA(key);
+        let encoded = encode_openssh(&pair, None).unwrap();
+        let decoded = decode_openssh(&encoded, None).unwrap();
+        match (pair, decoded) {
+            (key::KeyPair::DSA(a), key::KeyPair::DSA(b)) => {
+                assert_eq!(a.pub_key().to_vec(), b.pub_key().to_vec())
+            }
+            _ => panic!("expected a DSA key pair to round-trip as DSA"),
+        }
+    }
+
+    #[cfg(feature = "openssl")]
+    #[test]
+    fn aes256_gcm_round_trips() {
+        let mut kdfoptions = Vec::new();
+        write_string(&mut kdfoptions, b"saltsaltsaltsalt");
+        write_u32(&mut kdfoptions, 16);
+
+        let plaintext = b"the quick brown fox jumps over the lazy dog";
+        let ciphertext = encrypt_secret_key(
+            b"aes256-gcm@openssh.com",
+            &kdfoptions,
+            "hunter2",
+            plaintext,
+        )
+        .unwrap();
+        let decrypted = decrypt_secret_key(
+            b"aes256-gcm@openssh.com",
+            b"bcrypt",
+            &kdfoptions,
+            Some("hunter2"),
+            &ciphertext,
+        )
+        .unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn empty_input_is_rejected_without_panicking() {
+        assert!(decode_openssh(&[], None).is_err());
+    }
+
+    #[test]
+    fn short_input_is_rejected_without_panicking() {
+        assert!(decode_openssh(b"short", None).is_err());
+    }
+
+    #[test]
+    fn truncated_key_is_rejected_without_panicking() {
+        let pair = key::KeyPair::generate_ed25519();
+        let encoded = encode_openssh(&pair, None).unwrap();
+        assert!(decode_openssh(&encoded[..encoded.len() - 10], None).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use crate::Error;
+use ssh_libsodium as sodium;
+
+/// Keys for elliptic curve Ed25519 cryptography.
+pub mod ed25519 {
+    pub use ssh_libsodium::ed25519::{keypair, sign_detached, verify_detached, PublicKey, SecretKey};
+}
+
+/// The hash function used when signing or verifying with an RSA key.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub enum SignatureHash {
+    /// SHA2, 256 bits.
+    SHA2_256,
+    /// SHA2, 512 bits.
+    SHA2_512,
+    /// SHA1.
+    SHA1,
+}
+
+/// A public key.
+pub enum PublicKey {
+    Ed25519(sodium::ed25519::PublicKey),
+    #[cfg(feature = "openssl")]
+    RSA {
+        key: openssl::rsa::Rsa<openssl::pkey::Public>,
+        hash: SignatureHash,
+    },
+    #[cfg(feature = "p256")]
+    P256(p256::PublicKey),
+    #[cfg(feature = "p384")]
+    P384(p384::PublicKey),
+    #[cfg(feature = "p521")]
+    P521(p521::PublicKey),
+    #[cfg(feature = "openssl")]
+    DSA(openssl::dsa::Dsa<openssl::pkey::Public>),
+}
+
+/// A key pair, i.e. a public key and the corresponding private key.
+pub enum KeyPair {
+    Ed25519(sodium::ed25519::SecretKey),
+    #[cfg(feature = "openssl")]
+    RSA {
+        key: openssl::rsa::Rsa<openssl::pkey::Private>,
+        hash: SignatureHash,
+    },
+    #[cfg(feature = "p256")]
+    P256(p256::SecretKey),
+    #[cfg(feature = "p384")]
+    P384(p384::SecretKey),
+    #[cfg(feature = "p521")]
+    P521(p521::SecretKey),
+    #[cfg(feature = "openssl")]
+    DSA(openssl::dsa::Dsa<openssl::pkey::Private>),
+}
+
+impl KeyPair {
+    /// Name of this key algorithm, as used on the wire.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyPair::Ed25519(_) => "ssh-ed25519",
+            #[cfg(feature = "openssl")]
+            KeyPair::RSA { hash, .. } => match hash {
+                SignatureHash::SHA2_256 => "rsa-sha2-256",
+                SignatureHash::SHA2_512 => "rsa-sha2-512",
+                SignatureHash::SHA1 => "ssh-rsa",
+            },
+            #[cfg(feature = "p256")]
+            KeyPair::P256(_) => "ecdsa-sha2-nistp256",
+            #[cfg(feature = "p384")]
+            KeyPair::P384(_) => "ecdsa-sha2-nistp384",
+            #[cfg(feature = "p521")]
+            KeyPair::P521(_) => "ecdsa-sha2-nistp521",
+            #[cfg(feature = "openssl")]
+            KeyPair::DSA(_) => "ssh-dss",
+        }
+    }
+
+    /// Generate a fresh Ed25519 key pair.
+    pub fn generate_ed25519() -> Self {
+        let (_, secret) = sodium::ed25519::keypair();
+        KeyPair::Ed25519(secret)
+    }
+
+    /// Copy the public half of this key pair.
+    pub fn clone_public_key(&self) -> Result<PublicKey, Error> {
+        match self {
+            KeyPair::Ed25519(key) => {
+                let mut public = sodium::ed25519::PublicKey::new_zeroed();
+                public.key.clone_from_slice(&key.key[32..]);
+                Ok(PublicKey::Ed25519(public))
+            }
+            #[cfg(feature = "openssl")]
+            KeyPair::RSA { key, hash } => Ok(PublicKey::RSA {
+                key: openssl::rsa::Rsa::from_public_components(
+                    key.n().to_owned()?,
+                    key.e().to_owned()?,
+                )?,
+                hash: *hash,
+            }),
+            #[cfg(feature = "p256")]
+            KeyPair::P256(key) => Ok(PublicKey::P256(key.public_key())),
+            #[cfg(feature = "p384")]
+            KeyPair::P384(key) => Ok(PublicKey::P384(key.public_key())),
+            #[cfg(feature = "p521")]
+            KeyPair::P521(key) => Ok(PublicKey::P521(key.public_key())),
+            #[cfg(feature = "openssl")]
+            KeyPair::DSA(key) => Ok(PublicKey::DSA(openssl::dsa::Dsa::from_public_components(
+                key.p().to_owned()?,
+                key.q().to_owned()?,
+                key.g().to_owned()?,
+                key.pub_key().to_owned()?,
+            )?)),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Deal with SSH keys: decode and encode OpenSSH private keys, and
+//! encrypt arbitrary payloads to an SSH public key the way `age` wraps
+//! a file key to a recipient.
+
+pub mod encoding;
+pub mod key;
+
+mod format;
+pub use format::*;
+
+pub mod encrypt;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The key could not be read, for an unknown reason.
+    #[error("Could not read key")]
+    CouldNotReadKey,
+    /// The type of the key is unsupported.
+    #[error("Unsupported key type")]
+    UnsupportedKeyType(Vec<u8>),
+    /// The key is encrypted (should supply a password?).
+    #[error("The key is encrypted")]
+    KeyIsEncrypted,
+    /// The key's internal structure failed an integrity check (a
+    /// malformed/corrupted/truncated key, or a bad password).
+    #[error("Key integrity check failed")]
+    KeyIntegrity,
+    /// Index out of bounds while reading an SSH-encoded value.
+    #[error("Index out of bounds")]
+    IndexOutOfBounds,
+
+    #[cfg(any(feature = "openssl", feature = "openssl-cipher"))]
+    #[error(transparent)]
+    Openssl(#[from] openssl::error::ErrorStack),
+
+    /// A P256/P384/P521 elliptic-curve operation failed (invalid scalar,
+    /// point not on curve, etc). `p256`/`p384`/`p521` all re-export the
+    /// same underlying `elliptic_curve::Error` type, so this one variant
+    /// covers all three rather than three conflicting `#[from]` impls.
+    #[cfg(any(feature = "p256", feature = "p384", feature = "p521"))]
+    #[error("Elliptic curve operation failed: {0}")]
+    EllipticCurve(String),
+
+    #[cfg(feature = "rust-crypto-cipher")]
+    #[error(transparent)]
+    BlockMode(#[from] block_modes::BlockModeError),
+}
+
+pub(crate) const KEYTYPE_ED25519: &[u8] = b"ssh-ed25519";
+pub(crate) const KEYTYPE_RSA: &[u8] = b"ssh-rsa";
+pub(crate) const KEYTYPE_P256: &[u8] = b"ecdsa-sha2-nistp256";
+pub(crate) const KEYTYPE_P384: &[u8] = b"ecdsa-sha2-nistp384";
+pub(crate) const KEYTYPE_P521: &[u8] = b"ecdsa-sha2-nistp521";
+pub(crate) const KEYTYPE_DSS: &[u8] = b"ssh-dss";